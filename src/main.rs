@@ -1,27 +1,171 @@
+use std::collections::VecDeque;
 use std::f32::consts::TAU;
-use std::sync::{Arc, Mutex};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
-struct SineOsc {
+// External crates this binary depends on (see Cargo.toml): `cpal` for
+// cross-platform audio I/O, `anyhow` for error handling, and `hound` for
+// WAV reading/writing.
+
+/// Number of simultaneously-sounding voices in the pool.
+const NUM_VOICES: usize = 16;
+
+/// Divides the summed voice output down to leave headroom when several
+/// voices sound at once. A full pool of 16 voices at unity level still
+/// peaks at 4.0 before the master `amp` is applied.
+const HEADROOM: f32 = 4.0;
+
+/// Operators are evaluated in this fixed order so that any modulator is
+/// always computed before the operator it feeds.
+const EVAL_ORDER: [usize; 4] = [3, 2, 1, 0];
+
+/// How long amp/level changes take to glide to their new value, to avoid
+/// the zipper noise of stepping them instantly.
+const PARAM_SMOOTH_SECONDS: f32 = 0.02;
+
+/// Upper bound on how many interleaved samples the tee ring is allowed to
+/// hold. A slow or stalled sink (disk, a wedged TCP client) must never let
+/// the ring grow without bound, so once it's full the oldest samples are
+/// dropped to make room for new ones rather than accumulating forever.
+const TEE_RING_CAPACITY: usize = 1 << 20;
+
+/// A value that glides toward a target by a fixed per-sample step instead
+/// of jumping, so parameter changes don't produce audible clicks/zipper
+/// noise. `tick()` is meant to be called exactly once per sample.
+#[derive(Clone, Copy)]
+struct Smoothed {
+    actual: f32,
+    target: f32,
+    step: f32,
+}
+
+impl Smoothed {
+    fn new(value: f32) -> Self {
+        Self {
+            actual: value,
+            target: value,
+            step: 0.0,
+        }
+    }
+
+    /// Retargets over `glide_samples` samples; a non-positive value snaps
+    /// immediately instead.
+    fn set_target(&mut self, target: f32, glide_samples: f32) {
+        self.target = target;
+        self.step = if glide_samples > 0.0 {
+            (target - self.actual).abs() / glide_samples
+        } else {
+            0.0
+        };
+        if self.step == 0.0 {
+            self.actual = target;
+        }
+    }
+
+    /// Jumps straight to `value`, e.g. for a fresh voice with no prior
+    /// pitch to glide from.
+    fn snap(&mut self, value: f32) {
+        self.actual = value;
+        self.target = value;
+        self.step = 0.0;
+    }
+
+    fn tick(&mut self) -> f32 {
+        if self.actual != self.target {
+            if (self.actual - self.target).abs() <= self.step {
+                self.actual = self.target;
+            } else if self.actual < self.target {
+                self.actual += self.step;
+            } else {
+                self.actual -= self.step;
+            }
+        }
+        self.actual
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    /// Like `Square` but with an adjustable duty cycle instead of a fixed
+    /// 50%, giving a "skewed" pulse timbre.
+    Pulse,
+}
+
+struct Osc {
     phase: f32,
     phase_inc: f32,
+    waveform: Waveform,
+    duty: f32,
 }
 
-impl SineOsc {
+impl Osc {
     fn new(freq: f32, sample_rate: f32) -> Self {
         Self {
             phase: 0.0,
             phase_inc: freq * TAU / sample_rate,
+            waveform: Waveform::Sine,
+            duty: 0.5,
         }
     }
     fn set_freq(&mut self, freq: f32, sample_rate: f32) {
         self.phase_inc = freq * TAU / sample_rate;
     }
-    fn next(&mut self) -> f32 {
-        let v = self.phase.sin();
+
+    /// 2-sample polynomial correction applied around a discontinuity, per
+    /// Valimaki's PolyBLEP band-limiting technique: `t` is the normalized
+    /// phase distance from the discontinuity (as a fraction of a cycle)
+    /// and `dt` is the normalized phase increment (one sample's worth of
+    /// phase, also as a fraction of a cycle).
+    fn poly_blep(t: f32, dt: f32) -> f32 {
+        if t < dt {
+            let t = t / dt;
+            t + t - t * t - 1.0
+        } else if t > 1.0 - dt {
+            let t = (t - 1.0) / dt;
+            t * t + t + t + 1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Advance the phase accumulator as usual, but sample the waveform at
+    /// `phase + phase_mod` instead of perturbing `phase_inc`. Doing the
+    /// modulation in the phase domain means the carrier's own frequency
+    /// never actually changes, so there is no cumulative pitch drift like
+    /// the old instantaneous-frequency-deviation approach had.
+    fn next_with_phase_mod(&mut self, phase_mod: f32) -> f32 {
+        let phase = self.phase + phase_mod;
+        let t = (phase / TAU).rem_euclid(1.0);
+        let dt = (self.phase_inc / TAU).abs().max(1e-9);
+
+        let v = match self.waveform {
+            Waveform::Sine => phase.sin(),
+            Waveform::Saw => 2.0 * t - 1.0 - Self::poly_blep(t, dt),
+            Waveform::Square | Waveform::Pulse => {
+                let duty = if self.waveform == Waveform::Square {
+                    0.5
+                } else {
+                    self.duty.clamp(0.01, 0.99)
+                };
+                let mut v = if t < duty { 1.0 } else { -1.0 };
+                v += Self::poly_blep(t, dt);
+                v -= Self::poly_blep((t + 1.0 - duty).rem_euclid(1.0), dt);
+                v
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (t - 0.5).abs(),
+        };
+
         self.phase += self.phase_inc;
         if self.phase >= TAU {
             self.phase -= TAU;
@@ -30,14 +174,35 @@ impl SineOsc {
     }
 }
 
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum AdsrCurve {
+    Linear,
+    Exponential,
+}
+
+/// Attack/decay/release times are in seconds; `sustain_db` is the held
+/// level expressed in decibels (e.g. -12.0) and converted to a linear
+/// gain internally. In `Exponential` mode each segment is a one-pole
+/// filter toward its target, giving a natural-sounding curve instead of
+/// a linear ramp; the pole coefficients are derived from the segment
+/// times once, not recomputed per sample.
 #[derive(Clone, Copy)]
 struct Adsr {
     attack: f32,
     decay: f32,
-    sustain: f32,
+    sustain_db: f32,
     release: f32,
+    curve: AdsrCurve,
     state: AdsrState,
     level: f32,
+    sustain_gain: f32,
+    attack_coef: f32,
+    decay_coef: f32,
+    release_coef: f32,
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -50,15 +215,39 @@ enum AdsrState {
 }
 
 impl Adsr {
-    fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Self {
-        Self {
+    fn new(
+        attack: f32,
+        decay: f32,
+        sustain_db: f32,
+        release: f32,
+        curve: AdsrCurve,
+        sample_rate: f32,
+    ) -> Self {
+        let mut adsr = Self {
             attack: attack.max(1e-6),
             decay: decay.max(1e-6),
-            sustain,
+            sustain_db,
             release: release.max(1e-6),
+            curve,
             state: AdsrState::Idle,
             level: 0.0,
-        }
+            sustain_gain: db_to_gain(sustain_db),
+            attack_coef: 0.0,
+            decay_coef: 0.0,
+            release_coef: 0.0,
+        };
+        adsr.recompute_coefs(sample_rate);
+        adsr
+    }
+
+    /// Derives the one-pole filter coefficients from the current segment
+    /// times; only needs to run when attack/decay/release/sustain change,
+    /// never per sample.
+    fn recompute_coefs(&mut self, sample_rate: f32) {
+        self.sustain_gain = db_to_gain(self.sustain_db);
+        self.attack_coef = (-1.0 / (self.attack * sample_rate)).exp();
+        self.decay_coef = (-1.0 / (self.decay * sample_rate)).exp();
+        self.release_coef = (-1.0 / (self.release * sample_rate)).exp();
     }
 
     fn note_on(&mut self) {
@@ -76,44 +265,517 @@ impl Adsr {
             AdsrState::Idle => {
                 self.level = 0.0;
             }
-            AdsrState::Attack => {
-                self.level += dt / self.attack;
-                if self.level >= 1.0 {
-                    self.level = 1.0;
-                    self.state = AdsrState::Decay;
+            AdsrState::Attack => match self.curve {
+                AdsrCurve::Linear => {
+                    self.level += dt / self.attack;
+                    if self.level >= 1.0 {
+                        self.level = 1.0;
+                        self.state = AdsrState::Decay;
+                    }
                 }
-            }
-            AdsrState::Decay => {
-                self.level -= dt / self.decay * (1.0 - self.sustain);
-                if self.level <= self.sustain {
-                    self.level = self.sustain;
-                    self.state = AdsrState::Sustain;
+                AdsrCurve::Exponential => {
+                    self.level += (1.0 - self.level) * (1.0 - self.attack_coef);
+                    if self.level >= 0.999 {
+                        self.level = 1.0;
+                        self.state = AdsrState::Decay;
+                    }
                 }
-            }
+            },
+            AdsrState::Decay => match self.curve {
+                AdsrCurve::Linear => {
+                    self.level -= dt / self.decay * (1.0 - self.sustain_gain);
+                    if self.level <= self.sustain_gain {
+                        self.level = self.sustain_gain;
+                        self.state = AdsrState::Sustain;
+                    }
+                }
+                AdsrCurve::Exponential => {
+                    self.level += (self.sustain_gain - self.level) * (1.0 - self.decay_coef);
+                    if (self.level - self.sustain_gain).abs() < 1e-4 {
+                        self.level = self.sustain_gain;
+                        self.state = AdsrState::Sustain;
+                    }
+                }
+            },
             AdsrState::Sustain => {
-                self.level = self.sustain;
+                self.level = self.sustain_gain;
             }
-            AdsrState::Release => {
-                // scaled by current level to avoid weird jumps
-                self.level -= dt / self.release * (self.level.max(1e-6));
-                if self.level <= 0.0 {
-                    self.level = 0.0;
-                    self.state = AdsrState::Idle;
+            AdsrState::Release => match self.curve {
+                AdsrCurve::Linear => {
+                    // scaled by current level to avoid weird jumps
+                    self.level -= dt / self.release * (self.level.max(1e-6));
+                    if self.level <= 0.0 {
+                        self.level = 0.0;
+                        self.state = AdsrState::Idle;
+                    }
                 }
-            }
+                AdsrCurve::Exponential => {
+                    // one-pole decay from wherever the level currently is
+                    // down toward silence
+                    self.level *= self.release_coef;
+                    if self.level < 1e-4 {
+                        self.level = 0.0;
+                        self.state = AdsrState::Idle;
+                    }
+                }
+            },
         }
         self.level
     }
 }
 
-#[derive(Clone)]
-struct SynthState {
-    carrier_freq: f32,
-    mod_ratio: f32,
-    mod_index: f32,
-    amp: f32,
+/// One FM operator: its own ratio of the voice's base frequency, its own
+/// output level (doing double duty as phase-modulation depth when this
+/// operator feeds another one, per the classic Yamaha convention), a
+/// fixed detune offset in Hz, and its own envelope.
+#[derive(Clone, Copy)]
+struct Operator {
+    ratio: f32,
+    level: Smoothed,
+    detune: f32,
     adsr: Adsr,
-    gate: bool,
+    waveform: Waveform,
+    duty: f32,
+}
+
+impl Operator {
+    fn new(ratio: f32, level: f32, detune: f32, adsr: Adsr) -> Self {
+        Self {
+            ratio,
+            level: Smoothed::new(level),
+            detune,
+            adsr,
+            waveform: Waveform::Sine,
+            duty: 0.5,
+        }
+    }
+}
+
+/// Describes how the 4 operators feed each other for a given algorithm.
+/// `modulators[i]` lists the operators whose (already-scaled) output is
+/// summed into operator `i`'s phase-modulation input. `outputs` lists the
+/// operators summed into the final audible output. Entries are only ever
+/// fed forward from a higher operator index into a lower one, so
+/// evaluating operators in the fixed order 3, 2, 1, 0 is always a valid
+/// topological order.
+struct Algorithm {
+    modulators: [&'static [usize]; 4],
+    outputs: &'static [usize],
+}
+
+const ALGORITHMS: [Algorithm; 8] = [
+    // 0: op4 -> op3 -> op2 -> op1 -> out (classic chain)
+    Algorithm {
+        modulators: [&[1], &[2], &[3], &[]],
+        outputs: &[0],
+    },
+    // 1: op4 and op3 both modulate op2, which carries into op1 -> out
+    Algorithm {
+        modulators: [&[1], &[3, 2], &[], &[]],
+        outputs: &[0],
+    },
+    // 2: op4 modulates op3, op3 and op2 both modulate op1 -> out
+    Algorithm {
+        modulators: [&[2, 1], &[], &[3], &[]],
+        outputs: &[0],
+    },
+    // 3: two independent 2-op FM pairs: op4 modulates op2, op3 modulates
+    // op1, both carriers summed to out
+    Algorithm {
+        modulators: [&[2], &[3], &[], &[]],
+        outputs: &[0, 1],
+    },
+    // 4: op4 -> op2 -> op1 -> out, with op3 a parallel carrier
+    Algorithm {
+        modulators: [&[1], &[3], &[], &[]],
+        outputs: &[0, 2],
+    },
+    // 5: op4 modulates op1, op2, and op3 in parallel, all summed to out
+    Algorithm {
+        modulators: [&[3], &[3], &[3], &[]],
+        outputs: &[0, 1, 2],
+    },
+    // 6: op4 -> op3 -> op2 -> out, with op1 a parallel carrier
+    Algorithm {
+        modulators: [&[], &[2], &[3], &[]],
+        outputs: &[0, 1],
+    },
+    // 7: all four operators are parallel carriers summed to out
+    Algorithm {
+        modulators: [&[], &[], &[], &[]],
+        outputs: &[0, 1, 2, 3],
+    },
+];
+
+/// The current patch: algorithm and per-operator settings copied into
+/// every voice that gets triggered. Changes to the algorithm, ratios,
+/// detunes, waveforms and envelope curve only affect voices triggered
+/// from now on; operator level changes also glide on voices already
+/// sounding, and `amp` scales the whole mix at once.
+#[derive(Clone)]
+struct Patch {
+    algorithm: u8,
+    operators: [Operator; 4],
+    amp: Smoothed,
+    glide_seconds: f32,
+}
+
+/// A single sounding note: its own oscillator phases and its own copy of
+/// the operator envelopes, so voices progress independently of one
+/// another and of the shared patch.
+struct Voice {
+    id: u64,
+    note: u8,
+    freq: Smoothed,
+    algorithm: u8,
+    operators: [Operator; 4],
+    oscs: [Osc; 4],
+    released: bool,
+}
+
+impl Voice {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            id: 0,
+            note: 0,
+            freq: Smoothed::new(0.0),
+            algorithm: 0,
+            operators: [Operator::new(
+                1.0,
+                0.0,
+                0.0,
+                Adsr::new(0.01, 0.1, -2.0, 0.3, AdsrCurve::Exponential, sample_rate),
+            ); 4],
+            oscs: [
+                Osc::new(0.0, sample_rate),
+                Osc::new(0.0, sample_rate),
+                Osc::new(0.0, sample_rate),
+                Osc::new(0.0, sample_rate),
+            ],
+            released: false,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.operators.iter().all(|op| op.adsr.state == AdsrState::Idle)
+    }
+
+    fn is_releasing(&self) -> bool {
+        self.released && !self.is_idle()
+    }
+
+    fn current_level(&self) -> f32 {
+        self.operators.iter().map(|op| op.adsr.level).sum()
+    }
+
+    fn trigger(&mut self, id: u64, note: u8, base_freq: f32, patch: &Patch, glide_samples: f32) {
+        let was_sounding = !self.is_idle();
+        self.id = id;
+        self.note = note;
+        self.algorithm = patch.algorithm;
+        self.operators = patch.operators;
+        if was_sounding {
+            // Retriggered (a voice stolen while still sounding): slide from
+            // the pitch it was already playing rather than jumping.
+            self.freq.set_target(base_freq, glide_samples);
+        } else {
+            self.freq.snap(base_freq);
+        }
+        for op in &mut self.operators {
+            op.adsr.note_on();
+        }
+        self.released = false;
+    }
+
+    fn release(&mut self) {
+        self.released = true;
+        for op in &mut self.operators {
+            op.adsr.note_off();
+        }
+    }
+
+    fn process(&mut self, sample_rate: f32, dt: f32) -> f32 {
+        if self.is_idle() {
+            return 0.0;
+        }
+        let algorithm = &ALGORITHMS[self.algorithm as usize];
+        let mut op_out = [0.0f32; 4];
+        let base_freq = self.freq.tick();
+
+        for &i in &EVAL_ORDER {
+            let op = &mut self.operators[i];
+            let freq = (base_freq * op.ratio + op.detune).max(0.0);
+            self.oscs[i].set_freq(freq, sample_rate);
+            self.oscs[i].waveform = op.waveform;
+            self.oscs[i].duty = op.duty;
+
+            let phase_mod: f32 = algorithm.modulators[i].iter().map(|&m| op_out[m]).sum();
+            let env_level = op.adsr.next(dt);
+            let level = op.level.tick();
+            op_out[i] = self.oscs[i].next_with_phase_mod(phase_mod) * level * env_level;
+        }
+
+        let out_sum: f32 = algorithm.outputs.iter().map(|&i| op_out[i]).sum();
+        out_sum / algorithm.outputs.len() as f32
+    }
+}
+
+/// Fixed pool of voices with note-on/note-off allocation and voice
+/// stealing once the pool is exhausted.
+struct VoiceManager {
+    voices: Vec<Voice>,
+    next_id: u64,
+}
+
+impl VoiceManager {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            voices: (0..NUM_VOICES).map(|_| Voice::new(sample_rate)).collect(),
+            next_id: 1,
+        }
+    }
+
+    /// Picks a voice to (re)trigger: a fully idle voice if one is free,
+    /// otherwise steals the quietest voice already in its release phase,
+    /// otherwise steals the single oldest voice in the pool.
+    fn allocate(&mut self) -> usize {
+        if let Some(i) = self.voices.iter().position(|v| v.is_idle()) {
+            return i;
+        }
+        if let Some(i) = self
+            .voices
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.is_releasing())
+            .min_by(|a, b| a.1.current_level().partial_cmp(&b.1.current_level()).unwrap())
+            .map(|(i, _)| i)
+        {
+            return i;
+        }
+        self.voices
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, v)| v.id)
+            .map(|(i, _)| i)
+            .expect("voice pool is never empty")
+    }
+
+    fn note_on(&mut self, note: u8, patch: &Patch, sample_rate: f32) {
+        let idx = self.allocate();
+        let id = self.next_id;
+        self.next_id += 1;
+        let glide_samples = patch.glide_seconds * sample_rate;
+        self.voices[idx].trigger(id, note, midi_note_to_freq(note), patch, glide_samples);
+    }
+
+    fn note_off(&mut self, note: u8) {
+        for v in &mut self.voices {
+            if v.note == note && !v.is_idle() && !v.released {
+                v.release();
+            }
+        }
+    }
+
+    fn mix(&mut self, sample_rate: f32, dt: f32) -> f32 {
+        let sum: f32 = self.voices.iter_mut().map(|v| v.process(sample_rate, dt)).sum();
+        sum / HEADROOM
+    }
+}
+
+fn midi_note_to_freq(note: u8) -> f32 {
+    440.0 * 2f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+/// A parameter change pushed from the UI thread. `NoteOn`/`NoteOff` carry
+/// a MIDI note, the `Set*` variants update the live patch.
+enum Event {
+    NoteOn(u8),
+    NoteOff(u8),
+    SetAlgorithm(u8),
+    SetOpParam(usize, OpParam, f32),
+    SetOpWaveform(usize, Waveform, f32),
+    SetAmp(f32),
+    SetGlide(f32),
+    SetEnvCurve(AdsrCurve),
+}
+
+#[derive(Clone, Copy)]
+enum OpParam {
+    Ratio,
+    Level,
+    Detune,
+}
+
+/// Pushes an event onto the queue the audio callback drains at the top of
+/// every buffer. The `VecDeque` preserves push order, so events are
+/// applied in the order the UI thread sent them.
+fn push_event(queue: &Mutex<VecDeque<Event>>, event: Event) {
+    queue.lock().unwrap().push_back(event);
+}
+
+fn apply_event(event: Event, patch: &mut Patch, voices: &mut VoiceManager, sample_rate: f32) {
+    let smooth_samples = PARAM_SMOOTH_SECONDS * sample_rate;
+    match event {
+        Event::NoteOn(note) => voices.note_on(note, patch, sample_rate),
+        Event::NoteOff(note) => voices.note_off(note),
+        Event::SetAlgorithm(alg) => patch.algorithm = alg,
+        Event::SetOpParam(idx, field, value) => {
+            let op = &mut patch.operators[idx];
+            match field {
+                OpParam::Ratio => op.ratio = value,
+                OpParam::Level => op.level.set_target(value, smooth_samples),
+                OpParam::Detune => op.detune = value,
+            }
+            if matches!(field, OpParam::Level) {
+                // Levels also need to retarget any voices already sounding
+                // with this patch — they snapshotted `patch.operators` at
+                // trigger, so without this a live level change would only
+                // ever reach notes played after it.
+                for voice in voices.voices.iter_mut().filter(|v| !v.is_idle()) {
+                    voice.operators[idx].level.set_target(value, smooth_samples);
+                }
+            }
+        }
+        Event::SetOpWaveform(idx, waveform, duty) => {
+            let op = &mut patch.operators[idx];
+            op.waveform = waveform;
+            op.duty = duty;
+        }
+        Event::SetAmp(amp) => patch.amp.set_target(amp, smooth_samples),
+        Event::SetGlide(seconds) => patch.glide_seconds = seconds.max(0.0),
+        Event::SetEnvCurve(curve) => {
+            for op in &mut patch.operators {
+                op.adsr.curve = curve;
+            }
+        }
+    }
+}
+
+/// A destination the synth's output can be mirrored into, in addition to
+/// the default output device. Recording and streaming both go through
+/// this so the audio thread only ever has to deal with one interface.
+trait SampleSink: Send {
+    fn write_samples(&mut self, samples: &[f32]);
+    fn flush(&mut self);
+}
+
+/// Writes 16-bit PCM to a WAV file at the device's sample rate/channels.
+struct WavSink {
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+}
+
+impl WavSink {
+    fn create(path: &str, sample_rate: u32, channels: u16) -> Result<Self, anyhow::Error> {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let writer = hound::WavWriter::create(path, spec)?;
+        Ok(Self {
+            writer: Some(writer),
+        })
+    }
+}
+
+impl SampleSink for WavSink {
+    fn write_samples(&mut self, samples: &[f32]) {
+        if let Some(writer) = &mut self.writer {
+            for &s in samples {
+                let sample = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                let _ = writer.write_sample(sample);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            let _ = writer.flush();
+        }
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        // WavWriter only patches the RIFF/data chunk sizes on finalize, so
+        // a plain drop would leave the file malformed.
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.finalize();
+        }
+    }
+}
+
+/// Streams interleaved little-endian f32 frames to a connected TCP client.
+struct TcpSink {
+    stream: TcpStream,
+}
+
+impl SampleSink for TcpSink {
+    fn write_samples(&mut self, samples: &[f32]) {
+        let mut buf = Vec::with_capacity(samples.len() * 4);
+        for &s in samples {
+            buf.extend_from_slice(&s.to_le_bytes());
+        }
+        let _ = self.stream.write_all(&buf);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.stream.flush();
+    }
+}
+
+enum SinkCommand {
+    Add(Box<dyn SampleSink>),
+    StopAll,
+}
+
+/// Drains the tee ring buffer into every active sink. Runs on its own
+/// thread so slow disk or network I/O never stalls the audio callback;
+/// the callback only ever does a best-effort `try_lock` push into `ring`.
+fn run_sink_thread(
+    cmd_rx: mpsc::Receiver<SinkCommand>,
+    ring: Arc<Mutex<VecDeque<f32>>>,
+    sinks_active: Arc<AtomicBool>,
+    tee_dropped: Arc<AtomicU64>,
+) {
+    let mut sinks: Vec<Box<dyn SampleSink>> = Vec::new();
+    loop {
+        while let Ok(cmd) = cmd_rx.try_recv() {
+            match cmd {
+                SinkCommand::Add(sink) => {
+                    sinks.push(sink);
+                    sinks_active.store(true, Ordering::Relaxed);
+                }
+                SinkCommand::StopAll => {
+                    for mut sink in sinks.drain(..) {
+                        sink.flush();
+                    }
+                    sinks_active.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let samples: Vec<f32> = {
+            let mut q = ring.lock().unwrap();
+            q.drain(..).collect()
+        };
+        if !samples.is_empty() {
+            for sink in &mut sinks {
+                sink.write_samples(&samples);
+            }
+        }
+
+        let dropped = tee_dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            println!(
+                "Warning: a sink fell behind and {} teed samples were dropped",
+                dropped
+            );
+        }
+
+        thread::sleep(Duration::from_millis(5));
+    }
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -128,21 +790,43 @@ fn main() -> Result<(), anyhow::Error> {
     let channels = config.channels as usize;
     println!("Sample rate: {}, channels: {}", sample_rate, channels);
 
-    // initial synth state
-    let state = SynthState {
-        carrier_freq: 220.0,
-        mod_ratio: 2.0,
-        mod_index: 100.0,
-        amp: 0.2,
-        adsr: Adsr::new(0.01, 0.1, 0.8, 0.3),
-        gate: false,
+    // initial patch: algorithm 0 (chain) with op2 standing in for the old
+    // single modulator at the same 2:1 ratio and op3/op4 silent, so the
+    // default patch keeps the previous 2-operator layout.
+    let voice_adsr = Adsr::new(0.01, 0.1, -2.0, 0.3, AdsrCurve::Exponential, sample_rate);
+    let initial_patch = Patch {
+        algorithm: 0,
+        operators: [
+            Operator::new(1.0, 1.0, 0.0, voice_adsr),
+            Operator::new(2.0, 2.0, 0.0, voice_adsr),
+            Operator::new(1.0, 0.0, 0.0, voice_adsr),
+            Operator::new(1.0, 0.0, 0.0, voice_adsr),
+        ],
+        amp: Smoothed::new(0.2),
+        glide_seconds: 0.0,
     };
 
-    let shared = Arc::new(Mutex::new(state));
-    let shared_ui = shared.clone();
+    // The UI thread only ever pushes events into this queue; all
+    // patch/voice state lives on the audio thread, so the hot per-sample
+    // loop never has to take a lock.
+    let event_queue: Arc<Mutex<VecDeque<Event>>> = Arc::new(Mutex::new(VecDeque::new()));
+
+    let queue_ui = event_queue.clone();
+
+    // Sinks (WAV recording, TCP streaming) are teed off the audio thread
+    // through a ring buffer drained by their own dedicated thread, so
+    // disk/network I/O never blocks the callback.
+    let tee_ring: Arc<Mutex<VecDeque<f32>>> = Arc::new(Mutex::new(VecDeque::new()));
+    let sinks_active = Arc::new(AtomicBool::new(false));
+    let tee_dropped = Arc::new(AtomicU64::new(0));
+    let (sink_cmd_tx, sink_cmd_rx) = mpsc::channel::<SinkCommand>();
+    {
+        let tee_ring = tee_ring.clone();
+        let sinks_active = sinks_active.clone();
+        let tee_dropped = tee_dropped.clone();
+        thread::spawn(move || run_sink_thread(sink_cmd_rx, tee_ring, sinks_active, tee_dropped));
+    }
 
-    // Create oscillators local to the callback, but we construct them here so their memory lives long.
-    // We'll use move closure capturing arcs.
     let channels_copy = channels;
     let sample_rate_copy = sample_rate;
 
@@ -153,7 +837,11 @@ fn main() -> Result<(), anyhow::Error> {
         cpal::SampleFormat::F32 => build_and_run_stream::<f32>(
             &device,
             &config,
-            shared.clone(),
+            initial_patch.clone(),
+            event_queue.clone(),
+            tee_ring.clone(),
+            sinks_active.clone(),
+            tee_dropped.clone(),
             channels_copy,
             sample_rate_copy,
             err_fn,
@@ -161,7 +849,11 @@ fn main() -> Result<(), anyhow::Error> {
         cpal::SampleFormat::I16 => build_and_run_stream::<i16>(
             &device,
             &config,
-            shared.clone(),
+            initial_patch.clone(),
+            event_queue.clone(),
+            tee_ring.clone(),
+            sinks_active.clone(),
+            tee_dropped.clone(),
             channels_copy,
             sample_rate_copy,
             err_fn,
@@ -169,7 +861,11 @@ fn main() -> Result<(), anyhow::Error> {
         cpal::SampleFormat::U16 => build_and_run_stream::<u16>(
             &device,
             &config,
-            shared.clone(),
+            initial_patch.clone(),
+            event_queue.clone(),
+            tee_ring.clone(),
+            sinks_active.clone(),
+            tee_dropped.clone(),
             channels_copy,
             sample_rate_copy,
             err_fn,
@@ -179,7 +875,9 @@ fn main() -> Result<(), anyhow::Error> {
     stream.play()?;
 
     println!("FM synth running. Type commands (q to quit).");
-    println!("Commands: n <hz>, r <ratio>, i <index>, a <amp>, on, off");
+    println!(
+        "Commands: on <note>, off <note>, op <1-4> ratio/level/detune <v>, alg <0-7>, a <amp>, g <glide seconds>, env <lin|exp>, w <1-4> <sine|saw|square|tri|pulse> [duty], rec <file.wav>, serve <port>, stop"
+    );
 
     use std::io::{self, BufRead};
     let stdin = io::stdin();
@@ -188,54 +886,158 @@ fn main() -> Result<(), anyhow::Error> {
         let mut parts = l.trim().split_whitespace();
         if let Some(cmd) = parts.next() {
             match cmd {
-                "q" | "quit" => break,
-                "n" => {
-                    if let Some(s) = parts.next() {
-                        if let Ok(freq) = s.parse::<f32>() {
-                            let mut s = shared_ui.lock().unwrap();
-                            s.carrier_freq = freq.max(1.0);
-                            println!("Carrier freq = {}", s.carrier_freq);
+                "q" | "quit" => {
+                    // Give the sink thread a chance to finalize any open
+                    // WavSink before the process exits out from under it —
+                    // otherwise the file is left with an unpatched header.
+                    let _ = sink_cmd_tx.send(SinkCommand::StopAll);
+                    thread::sleep(Duration::from_millis(50));
+                    break;
+                }
+                "op" => {
+                    let op_idx = parts.next().and_then(|s| s.parse::<usize>().ok());
+                    let field = parts.next();
+                    let value = parts.next().and_then(|s| s.parse::<f32>().ok());
+                    match (op_idx, field, value) {
+                        (Some(n), Some(field), Some(v)) if (1..=4).contains(&n) => {
+                            let param = match field {
+                                "ratio" => Some(OpParam::Ratio),
+                                "level" => Some(OpParam::Level),
+                                "detune" => Some(OpParam::Detune),
+                                _ => None,
+                            };
+                            match param {
+                                Some(param) => {
+                                    let v = if matches!(param, OpParam::Detune) { v } else { v.max(0.0) };
+                                    push_event(
+                                        &queue_ui,
+                                        Event::SetOpParam(n - 1, param, v),
+                                    );
+                                    println!("op{} {}={}", n, field, v);
+                                }
+                                None => println!("Unknown op field (use ratio/level/detune)"),
+                            }
                         }
+                        _ => println!("Usage: op <1-4> <ratio|level|detune> <value>"),
                     }
                 }
-                "r" => {
+                "alg" => {
                     if let Some(s) = parts.next() {
-                        if let Ok(v) = s.parse::<f32>() {
-                            let mut s = shared_ui.lock().unwrap();
-                            s.mod_ratio = v.max(0.0);
-                            println!("Mod ratio = {}", s.mod_ratio);
+                        if let Ok(v) = s.parse::<u8>() {
+                            if v <= 7 {
+                                push_event(&queue_ui, Event::SetAlgorithm(v));
+                                println!("Algorithm = {}", v);
+                            } else {
+                                println!("Algorithm must be 0-7");
+                            }
                         }
                     }
                 }
-                "i" => {
+                "a" => {
                     if let Some(s) = parts.next() {
                         if let Ok(v) = s.parse::<f32>() {
-                            let mut s = shared_ui.lock().unwrap();
-                            s.mod_index = v.max(0.0);
-                            println!("Mod index = {}", s.mod_index);
+                            let v = v.clamp(0.0, 1.0);
+                            push_event(&queue_ui, Event::SetAmp(v));
+                            println!("Amplitude = {}", v);
                         }
                     }
                 }
-                "a" => {
+                "g" => {
                     if let Some(s) = parts.next() {
                         if let Ok(v) = s.parse::<f32>() {
-                            let mut s = shared_ui.lock().unwrap();
-                            s.amp = v.clamp(0.0, 1.0);
-                            println!("Amplitude = {}", s.amp);
+                            let v = v.max(0.0);
+                            push_event(&queue_ui, Event::SetGlide(v));
+                            println!("Glide = {} s", v);
                         }
                     }
                 }
+                "env" => match parts.next() {
+                    Some("lin") => {
+                        push_event(&queue_ui, Event::SetEnvCurve(AdsrCurve::Linear));
+                        println!("Envelope curve = linear");
+                    }
+                    Some("exp") => {
+                        push_event(
+                            &queue_ui,
+                            Event::SetEnvCurve(AdsrCurve::Exponential),
+                        );
+                        println!("Envelope curve = exponential");
+                    }
+                    _ => println!("Usage: env <lin|exp>"),
+                },
+                "w" => {
+                    let op_idx = parts.next().and_then(|s| s.parse::<usize>().ok());
+                    let waveform = parts.next().and_then(|s| match s {
+                        "sine" => Some(Waveform::Sine),
+                        "saw" => Some(Waveform::Saw),
+                        "square" => Some(Waveform::Square),
+                        "tri" => Some(Waveform::Triangle),
+                        "pulse" => Some(Waveform::Pulse),
+                        _ => None,
+                    });
+                    let duty = parts
+                        .next()
+                        .and_then(|s| s.parse::<f32>().ok())
+                        .unwrap_or(0.5);
+                    match (op_idx, waveform) {
+                        (Some(n), Some(waveform)) if (1..=4).contains(&n) => {
+                            push_event(
+                                &queue_ui,
+                                Event::SetOpWaveform(n - 1, waveform, duty.clamp(0.01, 0.99)),
+                            );
+                            println!("op{} waveform set", n);
+                        }
+                        _ => println!("Usage: w <1-4> <sine|saw|square|tri|pulse> [duty]"),
+                    }
+                }
                 "on" => {
-                    let mut s = shared_ui.lock().unwrap();
-                    s.gate = true;
-                    s.adsr.note_on();
-                    println!("Note ON");
+                    let note = parts.next().and_then(|s| s.parse::<u8>().ok()).unwrap_or(60);
+                    push_event(&queue_ui, Event::NoteOn(note));
+                    println!("Note ON {}", note);
                 }
                 "off" => {
-                    let mut s = shared_ui.lock().unwrap();
-                    s.gate = false;
-                    s.adsr.note_off();
-                    println!("Note OFF");
+                    let note = parts.next().and_then(|s| s.parse::<u8>().ok()).unwrap_or(60);
+                    push_event(&queue_ui, Event::NoteOff(note));
+                    println!("Note OFF {}", note);
+                }
+                "rec" => {
+                    if let Some(path) = parts.next() {
+                        match WavSink::create(path, sample_rate_copy as u32, channels_copy as u16)
+                        {
+                            Ok(sink) => {
+                                let _ = sink_cmd_tx.send(SinkCommand::Add(Box::new(sink)));
+                                println!("Recording to {}", path);
+                            }
+                            Err(e) => println!("Could not open {}: {}", path, e),
+                        }
+                    } else {
+                        println!("Usage: rec <file.wav>");
+                    }
+                }
+                "serve" => {
+                    // Loopback-only by default: this streams raw, unauthenticated
+                    // audio to whoever connects, so it shouldn't be reachable
+                    // from other hosts without the user opting in explicitly.
+                    if let Some(port) = parts.next().and_then(|s| s.parse::<u16>().ok()) {
+                        let sink_cmd_tx = sink_cmd_tx.clone();
+                        thread::spawn(move || match TcpListener::bind(("127.0.0.1", port)) {
+                            Ok(listener) => {
+                                println!("Listening for raw f32 samples on port {}", port);
+                                for stream in listener.incoming().flatten() {
+                                    println!("TCP sink connected");
+                                    let _ = sink_cmd_tx
+                                        .send(SinkCommand::Add(Box::new(TcpSink { stream })));
+                                }
+                            }
+                            Err(e) => println!("Could not bind port {}: {}", port, e),
+                        });
+                    } else {
+                        println!("Usage: serve <port>");
+                    }
+                }
+                "stop" => {
+                    let _ = sink_cmd_tx.send(SinkCommand::StopAll);
+                    println!("Stopped all sinks");
                 }
                 _ => println!("Unknown command"),
             }
@@ -249,7 +1051,11 @@ fn main() -> Result<(), anyhow::Error> {
 fn build_and_run_stream<T>(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
-    shared: Arc<Mutex<SynthState>>,
+    initial_patch: Patch,
+    event_queue: Arc<Mutex<VecDeque<Event>>>,
+    tee_ring: Arc<Mutex<VecDeque<f32>>>,
+    sinks_active: Arc<AtomicBool>,
+    tee_dropped: Arc<AtomicU64>,
     channels: usize,
     sample_rate: f32,
     err_fn: impl Fn(cpal::StreamError) + Send + Sync + 'static,
@@ -257,73 +1063,68 @@ fn build_and_run_stream<T>(
 where
     T: cpal::Sample,
 {
-    // Local oscillators used by the callback
-    let mut carrier = SineOsc::new(220.0, sample_rate);
-    let mut modulator = SineOsc::new(440.0, sample_rate);
-
     // time delta per sample
     let dt = 1.0 / sample_rate;
 
+    // All of this lives on the audio thread only; the UI thread never
+    // touches it directly.
+    let mut patch = initial_patch;
+    let mut voices = VoiceManager::new(sample_rate);
+    let mut pending: VecDeque<Event> = VecDeque::new();
+    let mut tee_buf: Vec<f32> = Vec::new();
+
     let stream = device.build_output_stream(
         config,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            // audio callback
-            // data length = frames * channels
-            let mut idx = 0;
-            while idx < data.len() {
-                // snapshot state
-                let snapshot = {
-                    let s = shared.lock().unwrap();
-                    s.clone()
-                };
+            // Pull in whatever events arrived since the last buffer and
+            // apply them all before producing any audio, preserving the
+            // order the UI thread pushed them in. A try_lock means the
+            // audio thread never blocks waiting on the UI thread; if the
+            // lock is briefly held we just pick those events up on the
+            // next callback instead of stalling here.
+            if let Ok(mut incoming) = event_queue.try_lock() {
+                pending.append(&mut incoming);
+            }
+            for event in pending.drain(..) {
+                apply_event(event, &mut patch, &mut voices, sample_rate);
+            }
 
-                // set oscillator frequencies according to snapshot
-                let carrier_freq = snapshot.carrier_freq;
-                let mod_freq = snapshot.carrier_freq * snapshot.mod_ratio;
-
-                carrier.set_freq(carrier_freq, sample_rate);
-                modulator.set_freq(mod_freq, sample_rate);
-
-                // generate one sample (mono) with FM: carrier phase is modulated
-                // instantaneous frequency offset = mod_index * modulator_sample
-                // We implement phase modulation via adding to carrier phase increment (approx).
-                // Better approach: compute modulator value and add to carrier phase directly:
-                let mod_sample = modulator.next();
-                // frequency deviation in Hz
-                let freq_deviation = snapshot.mod_index * mod_sample;
-                // compute instantaneous carrier phase increment
-                let inst_phase_inc = (carrier_freq + freq_deviation) * TAU / sample_rate;
-
-                // advance carrier manually using inst_phase_inc
-                // (we cheat a bit and override carrier.phase_inc for this sample)
-                let prev_inc = carrier.phase_inc;
-                carrier.phase_inc = inst_phase_inc;
-                let sample = carrier.next();
-                carrier.phase_inc = prev_inc; // restore nominal inc (will be set next loop anyway)
-
-                // envelope
-                let mut s2 = shared.lock().unwrap();
-                // update the ADSR in shared state and get envelope value
-                // If gate toggled, ADSR state already set by UI; here we just step it
-                let env_level = {
-                    let mut adsr_local = s2.adsr;
-                    // step envelope dt
-                    let level = {
-                        let mut ad = adsr_local;
-                        ad.next(dt)
-                    };
-                    // write back updated ADSR state into shared
-                    s2.adsr = adsr_local;
-                    level
-                };
+            let tee = sinks_active.load(Ordering::Relaxed);
+            if tee {
+                tee_buf.clear();
+            }
 
-                // final amplitude
-                let out = sample * env_level * snapshot.amp;
+            let frames = data.len() / channels;
+            let mut idx = 0;
+            for _ in 0..frames {
+                let out = voices.mix(sample_rate, dt) * patch.amp.tick();
 
-                // write to all channels (stereo duplicate)
-                for ch in 0..channels {
+                for _ in 0..channels {
                     data[idx] = cpal::Sample::from::<f32>(&out);
                     idx += 1;
+                    if tee {
+                        // Every channel gets the same mono mix, so tee one
+                        // copy per channel to match what WavSink/TcpSink
+                        // were told the channel count is.
+                        tee_buf.push(out);
+                    }
+                }
+            }
+
+            // Best-effort: if the sink thread is momentarily holding the
+            // lock we just drop this buffer's worth of teed audio rather
+            // than stalling the callback.
+            if tee {
+                if let Ok(mut ring) = tee_ring.try_lock() {
+                    ring.extend(tee_buf.iter().copied());
+                    // A stalled sink (slow disk, a wedged TCP client) must
+                    // never let this grow without bound: cap it and drop
+                    // the oldest samples, reporting the loss off-thread.
+                    if ring.len() > TEE_RING_CAPACITY {
+                        let overflow = ring.len() - TEE_RING_CAPACITY;
+                        ring.drain(..overflow);
+                        tee_dropped.fetch_add(overflow as u64, Ordering::Relaxed);
+                    }
                 }
             }
         },